@@ -0,0 +1,45 @@
+//! YAML configuration file support. Lets a fleet of Shelly devices be listed
+//! once with names and InfluxDB tags instead of re-deriving them from raw IPs
+//! on every run; CLI flags still override whatever the file sets.
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub devices: Vec<DeviceConfig>,
+    #[serde(default)]
+    pub influx: InfluxConfig,
+    pub interval: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct DeviceConfig {
+    pub ip: String,
+    pub name: Option<String>,
+    /// "gen1" or "gen2" (anything else is treated as "unknown, probe it").
+    pub generation: Option<String>,
+    #[serde(default)]
+    pub tags: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Default)]
+pub struct InfluxConfig {
+    pub url: Option<String>,
+    pub database: Option<String>,
+    pub org: Option<String>,
+    pub token: Option<String>,
+    pub bucket: Option<String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        serde_yaml::from_str(&contents)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+}
@@ -0,0 +1,385 @@
+//! Finding Shelly devices on the local network, either by scanning with nmap
+//! or by browsing mDNS/DNS-SD service advertisements.
+
+use anyhow::{Context, Result};
+use futures_util::{pin_mut, stream, stream::StreamExt};
+use mdns::RecordKind;
+use std::collections::HashMap;
+use std::process::Command;
+use std::time::Duration;
+use tracing::debug;
+
+/// The mDNS/DNS-SD service types Shelly devices (and their underlying HTTP
+/// servers) advertise themselves under.
+const MDNS_SERVICE_TYPES: &[&str] = &["_shelly._tcp.local", "_http._tcp.local"];
+
+/// Which Shelly RPC dialect a device speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellyGeneration {
+    /// Gen1 devices (`GET /status`, `GET /settings`, ...).
+    Gen1,
+    /// Gen2+ devices, which expose the unified `/rpc/*` API.
+    Gen2Plus,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ShellyDevice {
+    pub ip: String,
+    pub hostname: Option<String>,
+    /// Advertised model/app name, only known when discovered via mDNS.
+    pub model: Option<String>,
+    /// HTTP port to probe, read from the mDNS SRV record. `None` means the
+    /// default HTTP port 80 (the case for `--config`/`--shelly-ip` devices
+    /// and anything discovered via nmap).
+    pub port: Option<u16>,
+    /// Friendly name from `--config`. mDNS discovery leaves it unset and puts
+    /// the advertised name in `hostname` instead.
+    pub name: Option<String>,
+    /// Skips the `GET /shelly` generation probe when already known, e.g. from
+    /// `--config` or an mDNS TXT record's `gen` key.
+    pub generation_hint: Option<ShellyGeneration>,
+    /// Extra InfluxDB tags to attach to every line emitted for this device,
+    /// typically set via `--config`.
+    pub tags: HashMap<String, String>,
+}
+
+/// Browse `_shelly._tcp.local` and `_http._tcp.local`, collecting responses for
+/// `timeout` before returning whatever was found. Devices that advertise under
+/// `_http._tcp.local` are kept only if their TXT record looks Shelly-ish (it
+/// carries an `id`, `gen` or `app` key), since plenty of unrelated gear speaks plain
+/// mDNS HTTP too.
+pub async fn discover_mdns(timeout: Duration) -> Result<Vec<ShellyDevice>> {
+    let mut devices: HashMap<String, ShellyDevice> = HashMap::new();
+
+    // One shared deadline for both service types, so `timeout` bounds the
+    // whole call instead of being handed out fresh to each pass. A listen
+    // stream never ends on its own, so each pass gets an equal share of the
+    // time that is left.
+    let deadline = tokio::time::Instant::now() + timeout;
+
+    for (pass, service_type) in MDNS_SERVICE_TYPES.iter().enumerate() {
+        let stream = mdns::discover::all(*service_type, timeout)?.listen();
+        pin_mut!(stream);
+
+        let passes_left = (MDNS_SERVICE_TYPES.len() - pass) as u32;
+        let now = tokio::time::Instant::now();
+        let pass_deadline = now + deadline.saturating_duration_since(now) / passes_left;
+
+        loop {
+            let remaining = pass_deadline.saturating_duration_since(tokio::time::Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            match tokio::time::timeout(remaining, stream.next()).await {
+                Ok(Some(Ok(response))) => {
+                    let is_shelly_service = *service_type == "_shelly._tcp.local";
+                    if let Some(device) = shelly_device_from_response(&response, !is_shelly_service) {
+                        devices.entry(device.ip.clone()).or_insert(device);
+                    }
+                }
+                Ok(Some(Err(e))) => debug!("mDNS response error: {}", e),
+                Ok(None) | Err(_) => break,
+            }
+        }
+    }
+
+    Ok(devices.into_values().collect())
+}
+
+/// Pull an IPv4 address (A record), HTTP port (SRV record) and Shelly TXT
+/// metadata out of one mDNS response. `require_txt_hint` rejects
+/// `_http._tcp.local` responses that don't carry a Shelly-specific TXT key
+/// (`id`, `gen`, or `app`), since that service type is too generic to trust
+/// on its own.
+fn shelly_device_from_response(
+    response: &mdns::Response,
+    require_txt_hint: bool,
+) -> Option<ShellyDevice> {
+    let ip = response
+        .records()
+        .find_map(|record| match record.kind {
+            RecordKind::A(addr) => Some(addr.to_string()),
+            _ => None,
+        })?;
+
+    // Port 80 is the default HTTP port; only keep it when it's something else
+    // worth overriding the default with.
+    let port = response.port().filter(|&port| port != 80);
+
+    let hostname = response.records().find_map(|record| match &record.kind {
+        RecordKind::PTR(name) => Some(name.trim_end_matches('.').to_string()),
+        _ => None,
+    });
+
+    let mut model = None;
+    let mut generation_hint = None;
+    let mut has_shelly_txt_hint = false;
+    for record in response.records() {
+        if let RecordKind::TXT(entries) = &record.kind {
+            for entry in entries {
+                if let Some((key, value)) = entry.split_once('=') {
+                    match key {
+                        "id" => has_shelly_txt_hint = true,
+                        "gen" => {
+                            has_shelly_txt_hint = true;
+                            generation_hint = match value {
+                                "1" => Some(ShellyGeneration::Gen1),
+                                _ => Some(ShellyGeneration::Gen2Plus),
+                            };
+                        }
+                        "app" => {
+                            has_shelly_txt_hint = true;
+                            model = Some(value.to_string());
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    if require_txt_hint && !has_shelly_txt_hint {
+        return None;
+    }
+
+    Some(ShellyDevice {
+        ip,
+        hostname,
+        model,
+        port,
+        generation_hint,
+        ..Default::default()
+    })
+}
+
+/// Run `nmap -sn` over `network` and probe each responding host's HTTP
+/// endpoints, fanning the probes out across up to `concurrency` tasks at
+/// once, to guess whether it's a Shelly device.
+///
+/// `nmap` itself runs on a blocking-task thread so the `Command::output()`
+/// call doesn't stall the Tokio runtime while it waits for the scan to finish.
+pub async fn discover_nmap(client: &reqwest::Client, network: &str, concurrency: usize) -> Result<Vec<ShellyDevice>> {
+    debug!("Running nmap scan on network: {}", network);
+
+    let network = network.to_string();
+    let output = tokio::task::spawn_blocking(move || {
+        Command::new("nmap")
+            .args([
+                "-sn", // Ping scan only
+                &network,
+            ])
+            .output()
+    })
+    .await
+    .context("nmap task panicked")??;
+
+    if !output.status.success() {
+        anyhow::bail!("nmap command failed: {}", String::from_utf8_lossy(&output.stderr));
+    }
+
+    let nmap_output = String::from_utf8_lossy(&output.stdout).into_owned();
+    debug!("nmap output: {}", nmap_output);
+
+    let candidates: Vec<(String, Option<String>)> = nmap_output
+        .lines()
+        .filter(|line| line.contains("Nmap scan report for"))
+        .filter_map(extract_device_info_from_nmap_line)
+        .collect();
+
+    let discovered_devices = stream::iter(candidates)
+        .map(|(ip, hostname)| {
+            let client = client.clone();
+            async move {
+                if is_potential_shelly_device(&client, &ip).await {
+                    Some(ShellyDevice { ip, hostname, ..Default::default() })
+                } else {
+                    None
+                }
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .filter_map(|device| async move { device })
+        .collect()
+        .await;
+
+    Ok(discovered_devices)
+}
+
+fn extract_device_info_from_nmap_line(line: &str) -> Option<(String, Option<String>)> {
+    // Parse "Nmap scan report for 192.168.1.100" or "Nmap scan report for hostname (192.168.1.100)"
+    if let Some(ip_start) = line.rfind('(') {
+        if let Some(ip_end) = line.rfind(')') {
+            let ip = line[ip_start + 1..ip_end].to_string();
+            // Extract hostname from "Nmap scan report for hostname (192.168.1.100)"
+            let hostname_part = &line[0..ip_start].trim();
+            if let Some(hostname_start) = hostname_part.rfind(' ') {
+                let hostname = hostname_part[hostname_start + 1..].trim().to_string();
+                if !hostname.is_empty() && hostname != ip {
+                    return Some((ip, Some(hostname)));
+                }
+            }
+            return Some((ip, None));
+        }
+    } else if let Some(ip_part) = line.split_whitespace().last() {
+        // Simple case: "Nmap scan report for 192.168.1.100"
+        if ip_part.chars().next().unwrap_or('a').is_ascii_digit() {
+            return Some((ip_part.to_string(), None));
+        }
+    }
+    None
+}
+
+async fn is_potential_shelly_device(client: &reqwest::Client, ip: &str) -> bool {
+    // First, try to connect to the Shelly device's web interface
+    let url = format!("http://{}/shelly", ip);
+
+    match client.get(&url).timeout(Duration::from_secs(3)).send().await {
+        Ok(response) => {
+            // log the response
+            debug!("Response from {}: {:?}", ip, response);
+            // Check if response looks like a Shelly device
+            if let Ok(text) = response.text().await {
+                let text_lower = text.to_lowercase();
+                // Exclude cameras explicitly (Picvision, Hikvision, etc.)
+                if text_lower.contains("picvision") ||
+                   text_lower.contains("hikvision") ||
+                   text_lower.contains("hik-vision") ||
+                   text_lower.contains("camera") ||
+                   text_lower.contains("ipcam") ||
+                   text_lower.contains("video") {
+                    return false;
+                }
+                return text_lower.contains("shelly");
+            }
+            false
+        }
+        Err(_) => {
+            // Also try the status endpoint
+            let status_url = format!("http://{}/status", ip);
+            match client.get(&status_url).timeout(Duration::from_secs(3)).send().await {
+                Ok(response) => {
+                    if let Ok(text) = response.text().await {
+                        let text_lower = text.to_lowercase();
+                        // Exclude cameras from status endpoint too (Picvision, Hikvision, etc.)
+                        if text_lower.contains("picvision") ||
+                           text_lower.contains("hikvision") ||
+                           text_lower.contains("hik-vision") ||
+                           text_lower.contains("camera") ||
+                           text_lower.contains("ipcam") ||
+                           text_lower.contains("video") {
+                            return false;
+                        }
+                        // Check for Shelly-specific fields in status response
+                        text_lower.contains("shelly") ||
+                        text_lower.contains("wifi_sta") ||
+                        text_lower.contains("meter") ||
+                        text_lower.contains("relay")
+                    } else {
+                        // If we can't read the response but got a successful status,
+                        // do additional checks
+                        check_shelly_endpoints(client, ip).await
+                    }
+                }
+                Err(_) => false,
+            }
+        }
+    }
+}
+
+async fn check_shelly_endpoints(client: &reqwest::Client, ip: &str) -> bool {
+    // Try additional Shelly-specific endpoints to confirm it's not a camera
+    let endpoints = ["/settings", "/ota", "/meter/0"];
+
+    for endpoint in &endpoints {
+        let url = format!("http://{}{}", ip, endpoint);
+        if let Ok(response) = client.get(&url).timeout(Duration::from_secs(2)).send().await {
+            if response.status().is_success() {
+                if let Ok(text) = response.text().await {
+                    let text_lower = text.to_lowercase();
+                    // Definitely exclude if it mentions cameras (Picvision, Hikvision, etc.)
+                    if text_lower.contains("picvision") ||
+                       text_lower.contains("hikvision") ||
+                       text_lower.contains("hik-vision") ||
+                       text_lower.contains("camera") ||
+                       text_lower.contains("ipcam") ||
+                       text_lower.contains("video") {
+                        return false;
+                    }
+                    // Look for Shelly-specific content
+                    if text_lower.contains("shelly") ||
+                       text_lower.contains("relay") ||
+                       text_lower.contains("meter") {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn record(kind: RecordKind) -> mdns::Record {
+        mdns::Record { name: "shellyplug-s-abc123.local".to_string(), class: dns_parser::Class::IN, ttl: 120, kind }
+    }
+
+    fn response(records: Vec<mdns::Record>) -> mdns::Response {
+        mdns::Response { answers: records, nameservers: Vec::new(), additional: Vec::new() }
+    }
+
+    #[test]
+    fn correlates_a_srv_and_txt_records() {
+        let response = response(vec![
+            record(RecordKind::A(Ipv4Addr::new(192, 168, 1, 42))),
+            record(RecordKind::SRV { priority: 0, weight: 0, port: 8080, target: "shellyplug-s-abc123.local".to_string() }),
+            record(RecordKind::TXT(vec!["id=shellyplug-s-abc123".to_string(), "gen=2".to_string(), "app=PlugS".to_string()])),
+        ]);
+
+        let device = shelly_device_from_response(&response, true).expect("should parse into a device");
+
+        assert_eq!(device.ip, "192.168.1.42");
+        assert_eq!(device.port, Some(8080));
+        assert_eq!(device.generation_hint, Some(ShellyGeneration::Gen2Plus));
+        assert_eq!(device.model.as_deref(), Some("PlugS"));
+    }
+
+    #[test]
+    fn default_http_port_is_not_kept() {
+        let response = response(vec![
+            record(RecordKind::A(Ipv4Addr::new(192, 168, 1, 42))),
+            record(RecordKind::SRV { priority: 0, weight: 0, port: 80, target: "shellyplug-s-abc123.local".to_string() }),
+            record(RecordKind::TXT(vec!["id=shellyplug-s-abc123".to_string()])),
+        ]);
+
+        let device = shelly_device_from_response(&response, true).expect("should parse into a device");
+
+        assert_eq!(device.port, None);
+    }
+
+    #[test]
+    fn rejects_generic_http_service_without_shelly_txt_hint() {
+        let response = response(vec![
+            record(RecordKind::A(Ipv4Addr::new(192, 168, 1, 99))),
+            record(RecordKind::TXT(vec!["path=/".to_string()])),
+        ]);
+
+        assert!(shelly_device_from_response(&response, true).is_none());
+    }
+
+    #[test]
+    fn gen1_txt_hint_is_recognized() {
+        let response = response(vec![
+            record(RecordKind::A(Ipv4Addr::new(192, 168, 1, 7))),
+            record(RecordKind::TXT(vec!["gen=1".to_string()])),
+        ]);
+
+        let device = shelly_device_from_response(&response, true).expect("should parse into a device");
+
+        assert_eq!(device.generation_hint, Some(ShellyGeneration::Gen1));
+    }
+}
@@ -0,0 +1,225 @@
+//! InfluxDB v1 line-protocol encoding and the `/write` HTTP client.
+
+use crate::shelly::DeviceMetrics;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+
+/// Fully resolved InfluxDB connection settings, after merging `--config` with
+/// any CLI overrides.
+pub struct InfluxSettings {
+    pub url: String,
+    /// InfluxDB v1 database name, used for the `/write` endpoint when `bucket`
+    /// isn't set.
+    pub database: String,
+    /// InfluxDB v2 organization, used for the `/api/v2/write` endpoint when
+    /// `bucket` is set.
+    pub org: Option<String>,
+    /// InfluxDB v2 API token, sent as an `Authorization: Token` header.
+    pub token: Option<String>,
+    /// InfluxDB v2 bucket name. Presence of a bucket selects the v2 write
+    /// path over the v1 `/write?db=` path.
+    pub bucket: Option<String>,
+}
+
+/// Turn one device's scraped metrics into InfluxDB line-protocol lines,
+/// tagging every line with `device=<ip>` plus any config-supplied tags.
+pub fn lines_for_metrics(
+    ip: &str,
+    extra_tags: &HashMap<String, String>,
+    metrics: &DeviceMetrics,
+    timestamp_ns: u128,
+) -> Vec<String> {
+    let extra = render_extra_tags(extra_tags);
+    let mut lines = Vec::new();
+
+    for channel in &metrics.channels {
+        let tags = format!("device={},channel={}{}", escape_tag_value(ip), channel.channel, extra);
+
+        let mut fields = Vec::new();
+        if let Some(power) = channel.power_watts {
+            fields.push(format!("power={}", power));
+        }
+        if let Some(voltage) = channel.voltage {
+            fields.push(format!("voltage={}", voltage));
+        }
+        if let Some(current) = channel.current {
+            fields.push(format!("current={}", current));
+        }
+        if let Some(total) = channel.energy_total_wh {
+            fields.push(format!("total={}", total));
+        }
+        if !fields.is_empty() {
+            lines.push(line_protocol("shelly_power", &tags, &fields, timestamp_ns));
+        }
+
+        if let Some(is_on) = channel.relay_on {
+            let fields = vec![format!("on={}i", if is_on { 1 } else { 0 })];
+            lines.push(line_protocol("shelly_relay", &tags, &fields, timestamp_ns));
+        }
+
+        if let Some(temp_c) = channel.temperature_c {
+            let fields = vec![format!("celsius={}", temp_c)];
+            lines.push(line_protocol("shelly_temperature", &tags, &fields, timestamp_ns));
+        }
+    }
+
+    let device_tags = format!("device={}{}", escape_tag_value(ip), extra);
+
+    if let Some(temp_c) = metrics.temperature_c {
+        let fields = vec![format!("celsius={}", temp_c)];
+        lines.push(line_protocol("shelly_temperature", &device_tags, &fields, timestamp_ns));
+    }
+
+    if let Some(rssi) = metrics.wifi_rssi_dbm {
+        let fields = vec![format!("rssi={}i", rssi)];
+        lines.push(line_protocol("shelly_wifi", &device_tags, &fields, timestamp_ns));
+    }
+
+    lines
+}
+
+/// POST a batch of line-protocol lines to InfluxDB. Uses the v2
+/// `/api/v2/write` endpoint (with an `Authorization: Token` header) when
+/// `settings.bucket` is set, otherwise falls back to the v1 `/write?db=`
+/// endpoint.
+pub async fn push(client: &reqwest::Client, settings: &InfluxSettings, lines: &[String]) -> Result<()> {
+    let body = lines.join("\n");
+
+    let url = match &settings.bucket {
+        Some(bucket) => {
+            let org = settings.org.as_deref().unwrap_or_default();
+            format!("{}/api/v2/write?org={}&bucket={}", settings.url, org, bucket)
+        }
+        None => format!("{}/write?db={}", settings.url, settings.database),
+    };
+
+    let mut request = client.post(&url).body(body);
+    if settings.bucket.is_some() {
+        if let Some(token) = &settings.token {
+            request = request.header("Authorization", format!("Token {}", token));
+        }
+    }
+
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("failed to POST to {}", url))?;
+
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "InfluxDB write to {} failed with status {}: {}",
+            url,
+            response.status(),
+            response.text().await.unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+/// Render a device's custom config tags as trailing `,key=value` segments,
+/// ready to append after the other tags in a line-protocol tag set.
+fn render_extra_tags(tags: &HashMap<String, String>) -> String {
+    let mut sorted: Vec<_> = tags.iter().collect();
+    sorted.sort_by_key(|(key, _)| key.as_str());
+    sorted
+        .into_iter()
+        .map(|(key, value)| format!(",{}={}", escape_tag_value(key), escape_tag_value(value)))
+        .collect()
+}
+
+/// Build a single InfluxDB line-protocol line from a measurement+tags prefix,
+/// a set of already-formatted `key=value` fields, and a nanosecond timestamp.
+fn line_protocol(measurement: &str, tags: &str, fields: &[String], timestamp_ns: u128) -> String {
+    format!(
+        "{},{} {} {}",
+        escape_measurement(measurement),
+        tags,
+        fields.join(","),
+        timestamp_ns
+    )
+}
+
+/// Escape a measurement name per InfluxDB line protocol: commas and spaces are
+/// significant delimiters there too.
+fn escape_measurement(value: &str) -> String {
+    value.replace(',', "\\,").replace(' ', "\\ ")
+}
+
+/// Escape a tag value per InfluxDB line protocol (commas, spaces, and `=` are
+/// significant delimiters in tag keys/values).
+fn escape_tag_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+pub fn timestamp_now_ns() -> u128 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shelly::ChannelMetrics;
+
+    #[test]
+    fn relay_state_gets_integer_suffix() {
+        let metrics = DeviceMetrics {
+            channels: vec![ChannelMetrics { channel: 0, relay_on: Some(true), ..Default::default() }],
+            ..Default::default()
+        };
+
+        let lines = lines_for_metrics("10.0.0.1", &HashMap::new(), &metrics, 1);
+
+        assert_eq!(lines, vec!["shelly_relay,device=10.0.0.1,channel=0 on=1i 1"]);
+    }
+
+    #[test]
+    fn power_fields_are_floats_without_a_suffix() {
+        let metrics = DeviceMetrics {
+            channels: vec![ChannelMetrics {
+                channel: 0,
+                power_watts: Some(12.5),
+                voltage: Some(230.0),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+
+        let lines = lines_for_metrics("10.0.0.1", &HashMap::new(), &metrics, 1);
+
+        assert_eq!(lines, vec!["shelly_power,device=10.0.0.1,channel=0 power=12.5,voltage=230 1"]);
+    }
+
+    #[test]
+    fn extra_tags_are_sorted_and_appended() {
+        let mut tags = HashMap::new();
+        tags.insert("room".to_string(), "kitchen".to_string());
+        tags.insert("circuit".to_string(), "lighting".to_string());
+        let metrics = DeviceMetrics { wifi_rssi_dbm: Some(-60), ..Default::default() };
+
+        let lines = lines_for_metrics("10.0.0.1", &tags, &metrics, 1);
+
+        assert_eq!(
+            lines,
+            vec!["shelly_wifi,device=10.0.0.1,circuit=lighting,room=kitchen rssi=-60i 1"]
+        );
+    }
+
+    #[test]
+    fn tag_values_are_escaped() {
+        assert_eq!(escape_tag_value("a,b=c d"), "a\\,b\\=c\\ d");
+        assert_eq!(escape_tag_value("back\\slash"), "back\\\\slash");
+    }
+
+    #[test]
+    fn measurement_names_are_escaped() {
+        assert_eq!(escape_measurement("a,b c"), "a\\,b\\ c");
+    }
+}
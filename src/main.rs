@@ -1,47 +1,136 @@
-use anyhow::Result;
-use clap::Parser;
-use std::process::Command;
-use tracing::{info, warn, debug};
-
-#[derive(Debug, Clone)]
-struct ShellyDevice {
-    ip: String,
-    hostname: Option<String>,
+use anyhow::{Context, Result};
+use clap::{Parser, ValueEnum};
+use futures_util::{stream, StreamExt};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tracing::{info, warn};
+
+mod config;
+mod discover;
+mod influx;
+mod metrics;
+mod resolve;
+mod shelly;
+
+use discover::{ShellyDevice, ShellyGeneration};
+use influx::InfluxSettings;
+
+/// Which backend `--discover` uses to find Shelly devices on the network.
+#[derive(Debug, Clone, Copy, ValueEnum, PartialEq, Eq)]
+enum DiscoverMethod {
+    /// Browse mDNS/DNS-SD advertisements. Finds only real Shelly services and
+    /// needs no external binary or root privileges.
+    Mdns,
+    /// Ping-sweep the network with nmap and probe each host's HTTP endpoints.
+    Nmap,
 }
 
 #[derive(Parser)]
 #[command(name = "shelly-scrape")]
 #[command(about = "Scrape data from Shelly power monitoring and push to Influx")]
 struct Args {
-    /// Shelly device IP address (use --discover to find devices automatically)
+    /// Shelly device IP address (use --discover or --config instead to
+    /// monitor more than one device)
     #[arg(short, long)]
     shelly_ip: Option<String>,
-    
-    /// Automatically discover Shelly devices using nmap
+
+    /// Automatically discover Shelly devices (see --discover-method)
     #[arg(short, long)]
     discover: bool,
-    
-    /// Network range to scan for Shelly devices (e.g., 192.168.1.0/24)
+
+    /// Network range to scan for Shelly devices (e.g., 192.168.1.0/24), used
+    /// when --discover-method is nmap
     #[arg(short, long, default_value = "192.168.1.0/24")]
     network: String,
-    
+
+    /// Device discovery backend to use with --discover
+    #[arg(long, value_enum, default_value = "mdns")]
+    discover_method: DiscoverMethod,
+
+    /// How long to listen for mDNS responses before giving up, in seconds
+    #[arg(long, default_value = "5")]
+    discover_timeout: u64,
+
+    /// YAML config file listing monitored devices and InfluxDB settings.
+    /// Values given here override the same settings in the config file.
+    #[arg(long)]
+    config: Option<PathBuf>,
+
     /// InfluxDB URL
-    #[arg(short, long, default_value = "http://localhost:8086")]
-    influx_url: String,
-    
+    #[arg(short, long)]
+    influx_url: Option<String>,
+
     /// InfluxDB database name
-    #[arg(long, default_value = "shelly_data")]
-    database: String,
-    
+    #[arg(long)]
+    database: Option<String>,
+
     /// Scrape interval in seconds
-    #[arg(long, default_value = "60")]
-    interval: u64,
-    
+    #[arg(long)]
+    interval: Option<u64>,
+
+    /// Run a Prometheus exporter on this address instead of pushing to
+    /// InfluxDB on an interval (e.g. 0.0.0.0:9102)
+    #[arg(long)]
+    serve_metrics: Option<SocketAddr>,
+
+    /// Maximum number of devices to probe/scrape concurrently
+    #[arg(long, default_value = "16")]
+    concurrency: usize,
+
+    /// DNS server to use for resolving device hostnames (defaults to the
+    /// system resolver), useful for .local names or static DHCP leases
+    #[arg(long)]
+    dns: Option<IpAddr>,
+
     /// Enable verbose debug logging
     #[arg(short, long)]
     verbose: bool,
 }
 
+const DEFAULT_INFLUX_URL: &str = "http://localhost:8086";
+const DEFAULT_DATABASE: &str = "shelly_data";
+const DEFAULT_INTERVAL_SECS: u64 = 60;
+
+/// Merge `--config`'s `influx:` section with CLI overrides, CLI taking
+/// precedence, falling back to the built-in defaults when neither sets a
+/// value.
+fn resolve_influx_settings(args: &Args, config: &config::Config) -> InfluxSettings {
+    InfluxSettings {
+        url: args
+            .influx_url
+            .clone()
+            .or_else(|| config.influx.url.clone())
+            .unwrap_or_else(|| DEFAULT_INFLUX_URL.to_string()),
+        database: args
+            .database
+            .clone()
+            .or_else(|| config.influx.database.clone())
+            .unwrap_or_else(|| DEFAULT_DATABASE.to_string()),
+        org: config.influx.org.clone(),
+        token: config.influx.token.clone(),
+        bucket: config.influx.bucket.clone(),
+    }
+}
+
+fn shelly_device_from_config(device: config::DeviceConfig) -> ShellyDevice {
+    let generation_hint = match device.generation.as_deref() {
+        Some("gen1") => Some(ShellyGeneration::Gen1),
+        Some("gen2") => Some(ShellyGeneration::Gen2Plus),
+        _ => None,
+    };
+
+    ShellyDevice {
+        ip: device.ip,
+        hostname: None,
+        model: None,
+        port: None,
+        name: device.name,
+        generation_hint,
+        tags: device.tags,
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -58,215 +147,196 @@ async fn main() -> Result<()> {
     }
     
     info!("Starting Shelly scraper");
-    
-    // Discover or use specified Shelly devices
-    let shelly_devices = if args.discover {
-        info!("Discovering Shelly devices on network: {}", args.network);
-        discover_shelly_devices(&args.network).await?
+
+    let config = match &args.config {
+        Some(path) => {
+            info!("Loading config from {}", path.display());
+            config::Config::load(path)?
+        }
+        None => config::Config::default(),
+    };
+
+    let influx = resolve_influx_settings(&args, &config);
+    let interval = args.interval.or(config.interval).unwrap_or(DEFAULT_INTERVAL_SECS);
+
+    let http_client = reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(10))
+        .build()
+        .context("failed to build HTTP client")?;
+
+    // Devices from --config take precedence since they're explicit; otherwise
+    // fall back to --discover or a single --shelly-ip.
+    let shelly_devices = if !config.devices.is_empty() {
+        config.devices.into_iter().map(shelly_device_from_config).collect()
+    } else if args.discover {
+        match args.discover_method {
+            DiscoverMethod::Mdns => {
+                info!(
+                    "Discovering Shelly devices via mDNS (timeout {}s)",
+                    args.discover_timeout
+                );
+                discover::discover_mdns(Duration::from_secs(args.discover_timeout)).await?
+            }
+            DiscoverMethod::Nmap => {
+                info!("Discovering Shelly devices on network: {}", args.network);
+                discover::discover_nmap(&http_client, &args.network, args.concurrency).await?
+            }
+        }
     } else if let Some(ip) = &args.shelly_ip {
-        vec![ShellyDevice { ip: ip.clone(), hostname: None }]
+        vec![ShellyDevice { ip: ip.clone(), ..Default::default() }]
     } else {
-        anyhow::bail!("Either specify --shelly-ip or use --discover to find devices");
+        anyhow::bail!("Either specify --shelly-ip, --config, or --discover to find devices");
     };
-    
+
     if shelly_devices.is_empty() {
         warn!("No Shelly devices found!");
         return Ok(());
     }
-    
+
+    let resolver = resolve::build_resolver(args.dns)?;
+    let shelly_devices = stream::iter(shelly_devices)
+        .map(|mut device| {
+            let resolver = &resolver;
+            async move {
+                match resolve::resolve(resolver, &device.ip).await {
+                    Ok(ip) if ip != device.ip => {
+                        if device.hostname.is_none() {
+                            device.hostname = Some(device.ip.clone());
+                        }
+                        device.ip = ip;
+                    }
+                    Ok(_) => {}
+                    Err(e) => warn!("Failed to resolve {}: {}", device.ip, e),
+                }
+                device
+            }
+        })
+        .buffered(args.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
     info!("Found {} Shelly device(s):", shelly_devices.len());
     for device in &shelly_devices {
-        if let Some(hostname) = &device.hostname {
-            info!("  {} ({})", device.ip, hostname);
-        } else {
-            info!("  {}", device.ip);
+        match (&device.name.as_ref().or(device.hostname.as_ref()), &device.model) {
+            (Some(label), Some(model)) => info!("  {} ({}, {})", device.ip, label, model),
+            (Some(label), None) => info!("  {} ({})", device.ip, label),
+            (None, Some(model)) => info!("  {} ({})", device.ip, model),
+            (None, None) => info!("  {}", device.ip),
         }
     }
-    info!("InfluxDB URL: {}", args.influx_url);
-    info!("Database: {}", args.database);
-    info!("Interval: {}s", args.interval);
-    
-    // Main scraping loop
+
+    if let Some(addr) = args.serve_metrics {
+        return metrics::serve(addr, shelly_devices, http_client, args.concurrency).await;
+    }
+
+    info!("InfluxDB URL: {}", influx.url);
+    info!("Database: {}", influx.database);
+    info!("Interval: {}s", interval);
+
+    // Main scraping loop, fanned out across up to --concurrency devices at once.
     loop {
-        for device in &shelly_devices {
-            match scrape_and_push(&args, &device.ip).await {
-                Ok(_) => {
-                    if let Some(hostname) = &device.hostname {
-                        info!("Successfully scraped and pushed data from {} ({})", device.ip, hostname);
-                    } else {
-                        info!("Successfully scraped and pushed data from {}", device.ip);
-                    }
-                },
-                Err(e) => {
-                    if let Some(hostname) = &device.hostname {
-                        warn!("Error during scrape from {} ({}): {}", device.ip, hostname, e);
-                    } else {
-                        warn!("Error during scrape from {}: {}", device.ip, e);
+        stream::iter(&shelly_devices)
+            .for_each_concurrent(args.concurrency.max(1), |device| {
+                let client = http_client.clone();
+                let influx = &influx;
+                async move {
+                    match scrape_and_push(&client, influx, device).await {
+                        Ok(_) => {
+                            if let Some(hostname) = &device.hostname {
+                                info!("Successfully scraped and pushed data from {} ({})", device.ip, hostname);
+                            } else {
+                                info!("Successfully scraped and pushed data from {}", device.ip);
+                            }
+                        }
+                        Err(e) => {
+                            if let Some(hostname) = &device.hostname {
+                                warn!("Error during scrape from {} ({}): {}", device.ip, hostname, e);
+                            } else {
+                                warn!("Error during scrape from {}: {}", device.ip, e);
+                            }
+                        }
                     }
-                },
-            }
-        }
-        
-        tokio::time::sleep(tokio::time::Duration::from_secs(args.interval)).await;
+                }
+            })
+            .await;
+
+        tokio::time::sleep(tokio::time::Duration::from_secs(interval)).await;
     }
 }
 
-async fn discover_shelly_devices(network: &str) -> Result<Vec<ShellyDevice>> {
-    info!("Running nmap scan on network: {}", network);
-    
-    // Run nmap to discover devices on ports commonly used by Shelly devices
-    let output = Command::new("nmap")
-        .args([
-            "-sn", // Ping scan only
-            network,
-        ])
-        .output()?;
-    
-    if !output.status.success() {
-        anyhow::bail!("nmap command failed: {}", String::from_utf8_lossy(&output.stderr));
-    }
-    
-    let nmap_output = String::from_utf8_lossy(&output.stdout);
-    debug!("nmap output: {}", nmap_output);
-    
-    // Extract IP addresses and hostnames from nmap output
-    let mut discovered_devices = Vec::new();
-    for line in nmap_output.lines() {
-        if line.contains("Nmap scan report for") {
-            if let Some((ip, hostname)) = extract_device_info_from_nmap_line(line) {
-                // Test if this might be a Shelly device by checking port 80
-                if is_potential_shelly_device(&ip).await {
-                    discovered_devices.push(ShellyDevice { ip, hostname });
-                }
-            }
-        }
+async fn scrape_and_push(client: &reqwest::Client, influx: &InfluxSettings, device: &ShellyDevice) -> Result<()> {
+    let metrics = shelly::scrape_device(client, device).await?;
+    let lines = influx::lines_for_metrics(&device.ip, &device.tags, &metrics, influx::timestamp_now_ns());
+
+    if lines.is_empty() {
+        warn!("No metrics collected from {}", device.ip);
+        return Ok(());
     }
-    
-    Ok(discovered_devices)
+
+    influx::push(client, influx, &lines).await
 }
 
-fn extract_device_info_from_nmap_line(line: &str) -> Option<(String, Option<String>)> {
-    // Parse "Nmap scan report for 192.168.1.100" or "Nmap scan report for hostname (192.168.1.100)"
-    if let Some(ip_start) = line.rfind('(') {
-        if let Some(ip_end) = line.rfind(')') {
-            let ip = line[ip_start + 1..ip_end].to_string();
-            // Extract hostname from "Nmap scan report for hostname (192.168.1.100)"
-            let hostname_part = &line[0..ip_start].trim();
-            if let Some(hostname_start) = hostname_part.rfind(' ') {
-                let hostname = hostname_part[hostname_start + 1..].trim().to_string();
-                if !hostname.is_empty() && hostname != ip {
-                    return Some((ip, Some(hostname)));
-                }
-            }
-            return Some((ip, None));
-        }
-    } else if let Some(ip_part) = line.split_whitespace().last() {
-        // Simple case: "Nmap scan report for 192.168.1.100"
-        if ip_part.chars().next().unwrap_or('a').is_ascii_digit() {
-            return Some((ip_part.to_string(), None));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `Args` with every field at its "nothing passed on the CLI" value, so
+    /// tests only need to set the fields they care about.
+    fn bare_args() -> Args {
+        Args {
+            shelly_ip: None,
+            discover: false,
+            network: "192.168.1.0/24".to_string(),
+            discover_method: DiscoverMethod::Mdns,
+            discover_timeout: 5,
+            config: None,
+            influx_url: None,
+            database: None,
+            interval: None,
+            serve_metrics: None,
+            concurrency: 16,
+            dns: None,
+            verbose: false,
         }
     }
-    None
-}
 
-async fn is_potential_shelly_device(ip: &str) -> bool {
-    let client = reqwest::Client::new();
-    
-    // First, try to connect to the Shelly device's web interface
-    let url = format!("http://{}/shelly", ip);
-    
-    match client.get(&url).timeout(std::time::Duration::from_secs(3)).send().await {
-        Ok(response) => {
-            // log the response
-            debug!("Response from {}: {:?}", ip, response);
-            // Check if response looks like a Shelly device
-            if let Ok(text) = response.text().await {
-                let text_lower = text.to_lowercase();
-                // Exclude cameras explicitly (Picvision, Hikvision, etc.)
-                if text_lower.contains("picvision") || 
-                   text_lower.contains("hikvision") || 
-                   text_lower.contains("hik-vision") ||
-                   text_lower.contains("camera") ||
-                   text_lower.contains("ipcam") ||
-                   text_lower.contains("video") {
-                    return false;
-                }
-                return text_lower.contains("shelly");
-            }
-            false
-        }
-        Err(_) => {
-            // Also try the status endpoint
-            let status_url = format!("http://{}/status", ip);
-            match client.get(&status_url).timeout(std::time::Duration::from_secs(3)).send().await {
-                Ok(response) => {
-                    if let Ok(text) = response.text().await {
-                        let text_lower = text.to_lowercase();
-                        // Exclude cameras from status endpoint too (Picvision, Hikvision, etc.)
-                        if text_lower.contains("picvision") || 
-                           text_lower.contains("hikvision") || 
-                           text_lower.contains("hik-vision") ||
-                           text_lower.contains("camera") ||
-                           text_lower.contains("ipcam") ||
-                           text_lower.contains("video") {
-                            return false;
-                        }
-                        // Check for Shelly-specific fields in status response
-                        text_lower.contains("shelly") || 
-                        text_lower.contains("wifi_sta") || 
-                        text_lower.contains("meter") ||
-                        text_lower.contains("relay")
-                    } else {
-                        // If we can't read the response but got a successful status, 
-                        // do additional checks
-                        check_shelly_endpoints(&client, ip).await
-                    }
-                }
-                Err(_) => false,
-            }
-        }
+    #[test]
+    fn cli_flags_override_config_file_influx_settings() {
+        let args = Args { influx_url: Some("http://cli:8086".to_string()), ..bare_args() };
+        let mut config = config::Config::default();
+        config.influx.url = Some("http://file:8086".to_string());
+        config.influx.database = Some("file_db".to_string());
+
+        let influx = resolve_influx_settings(&args, &config);
+
+        assert_eq!(influx.url, "http://cli:8086");
+        assert_eq!(influx.database, "file_db");
     }
-}
 
-async fn check_shelly_endpoints(client: &reqwest::Client, ip: &str) -> bool {
-    // Try additional Shelly-specific endpoints to confirm it's not a camera
-    let endpoints = ["/settings", "/ota", "/meter/0"];
-    
-    for endpoint in &endpoints {
-        let url = format!("http://{}{}", ip, endpoint);
-        if let Ok(response) = client.get(&url).timeout(std::time::Duration::from_secs(2)).send().await {
-            if response.status().is_success() {
-                if let Ok(text) = response.text().await {
-                    let text_lower = text.to_lowercase();
-                    // Definitely exclude if it mentions cameras (Picvision, Hikvision, etc.)
-                    if text_lower.contains("picvision") || 
-                       text_lower.contains("hikvision") || 
-                       text_lower.contains("hik-vision") ||
-                       text_lower.contains("camera") ||
-                       text_lower.contains("ipcam") ||
-                       text_lower.contains("video") {
-                        return false;
-                    }
-                    // Look for Shelly-specific content
-                    if text_lower.contains("shelly") || 
-                       text_lower.contains("relay") || 
-                       text_lower.contains("meter") {
-                        return true;
-                    }
-                }
-            }
-        }
+    #[test]
+    fn falls_back_to_defaults_when_neither_cli_nor_config_set_a_value() {
+        let args = bare_args();
+        let config = config::Config::default();
+
+        let influx = resolve_influx_settings(&args, &config);
+
+        assert_eq!(influx.url, DEFAULT_INFLUX_URL);
+        assert_eq!(influx.database, DEFAULT_DATABASE);
     }
-    
-    false
-}
 
-async fn scrape_and_push(args: &Args, shelly_ip: &str) -> Result<()> {
-    // TODO: Implement Shelly API scraping
-    // TODO: Implement InfluxDB pushing
-    
-    println!("Scraping data from Shelly device at {}", shelly_ip);
-    println!("Would push to InfluxDB at {}", args.influx_url);
-    
-    Ok(())
+    #[test]
+    fn v2_settings_always_come_from_config_since_cli_has_no_flags_for_them() {
+        let args = bare_args();
+        let mut config = config::Config::default();
+        config.influx.org = Some("my-org".to_string());
+        config.influx.token = Some("secret".to_string());
+        config.influx.bucket = Some("shelly".to_string());
+
+        let influx = resolve_influx_settings(&args, &config);
+
+        assert_eq!(influx.org.as_deref(), Some("my-org"));
+        assert_eq!(influx.token.as_deref(), Some("secret"));
+        assert_eq!(influx.bucket.as_deref(), Some("shelly"));
+    }
 }
@@ -0,0 +1,145 @@
+//! Prometheus exporter mode: a small HTTP server that scrapes every
+//! configured/discovered device on each request to `/metrics`, rendering the
+//! same readings the InfluxDB push path uses in Prometheus text exposition
+//! format.
+
+use crate::discover::ShellyDevice;
+use crate::shelly::{self, DeviceMetrics};
+use anyhow::{Context, Result};
+use axum::extract::State;
+use axum::routing::get;
+use axum::Router;
+use futures_util::{stream, StreamExt};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tracing::{info, warn};
+
+#[derive(Clone)]
+struct AppState {
+    devices: Arc<Vec<Arc<ShellyDevice>>>,
+    client: reqwest::Client,
+    concurrency: usize,
+}
+
+/// Serve `/metrics` on `addr` until the process is killed, scraping every
+/// device concurrently (bounded by `concurrency`) on each request using the
+/// shared `client`.
+pub async fn serve(
+    addr: SocketAddr,
+    devices: Vec<ShellyDevice>,
+    client: reqwest::Client,
+    concurrency: usize,
+) -> Result<()> {
+    let devices = devices.into_iter().map(Arc::new).collect();
+    let state = AppState { devices: Arc::new(devices), client, concurrency };
+    let app = Router::new().route("/metrics", get(metrics_handler)).with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| format!("failed to bind {}", addr))?;
+    info!("Serving Prometheus metrics on http://{}/metrics", addr);
+
+    axum::serve(listener, app).await.context("metrics server failed")
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> String {
+    let scrapes = stream::iter(state.devices.iter().cloned())
+        .map(|device| {
+            let client = state.client.clone();
+            async move {
+                match shelly::scrape_device(&client, &device).await {
+                    Ok(metrics) => (device, Some(metrics)),
+                    Err(e) => {
+                        warn!("failed to scrape {} for /metrics: {}", device.ip, e);
+                        (device, None)
+                    }
+                }
+            }
+        })
+        .buffered(state.concurrency.max(1))
+        .collect::<Vec<_>>()
+        .await;
+
+    render(&scrapes)
+}
+
+/// Render a batch of per-device scrapes as Prometheus text exposition format.
+fn render(scrapes: &[(Arc<ShellyDevice>, Option<DeviceMetrics>)]) -> String {
+    let mut power = Vec::new();
+    let mut energy = Vec::new();
+    let mut temperature = Vec::new();
+    let mut wifi = Vec::new();
+
+    for (device, metrics) in scrapes {
+        let Some(metrics) = metrics else { continue };
+        let name = device.name.as_deref().or(device.hostname.as_deref());
+
+        for channel in &metrics.channels {
+            let labels = labels(&device.ip, Some(channel.channel), name);
+            if let Some(v) = channel.power_watts {
+                power.push(format!("shelly_power_watts{{{}}} {}", labels, v));
+            }
+            if let Some(v) = channel.energy_total_wh {
+                energy.push(format!("shelly_energy_total_wh{{{}}} {}", labels, v));
+            }
+            if let Some(v) = channel.temperature_c {
+                temperature.push(format!("shelly_temperature_celsius{{{}}} {}", labels, v));
+            }
+        }
+
+        if let Some(v) = metrics.temperature_c {
+            let labels = labels(&device.ip, None, name);
+            temperature.push(format!("shelly_temperature_celsius{{{}}} {}", labels, v));
+        }
+        if let Some(v) = metrics.wifi_rssi_dbm {
+            let labels = labels(&device.ip, None, name);
+            wifi.push(format!("shelly_wifi_rssi_dbm{{{}}} {}", labels, v));
+        }
+    }
+
+    let mut out = String::new();
+    append_metric(&mut out, "shelly_power_watts", "gauge", "Instantaneous real power draw, in watts.", &power);
+    append_metric(&mut out, "shelly_energy_total_wh", "counter", "Cumulative energy consumption, in watt-hours.", &energy);
+    append_metric(&mut out, "shelly_temperature_celsius", "gauge", "Device or channel temperature, in degrees Celsius.", &temperature);
+    append_metric(&mut out, "shelly_wifi_rssi_dbm", "gauge", "WiFi signal strength, in dBm.", &wifi);
+    out
+}
+
+fn append_metric(out: &mut String, name: &str, metric_type: &str, help: &str, samples: &[String]) {
+    if samples.is_empty() {
+        return;
+    }
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, metric_type);
+    for sample in samples {
+        let _ = writeln!(out, "{}", sample);
+    }
+}
+
+/// Build a Prometheus label set in `device, channel, name` order, matching
+/// how the InfluxDB tags are laid out.
+fn labels(ip: &str, channel: Option<usize>, name: Option<&str>) -> String {
+    let mut parts = vec![format!("device=\"{}\"", escape_label(ip))];
+    if let Some(channel) = channel {
+        parts.push(format!("channel=\"{}\"", channel));
+    }
+    if let Some(name) = name {
+        parts.push(format!("name=\"{}\"", escape_label(name)));
+    }
+    parts.join(",")
+}
+
+fn escape_label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_backslashes_quotes_and_newlines() {
+        assert_eq!(escape_label("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
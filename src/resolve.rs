@@ -0,0 +1,45 @@
+//! Async hostname resolution, so devices in `--config` or discovered by name
+//! (e.g. a `.local` mDNS hostname, or a static DHCP lease name) can be reached
+//! without hardcoding an IP. Uses the system resolver unless `--dns`
+//! overrides it with a single nameserver.
+
+use anyhow::{Context, Result};
+use hickory_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+use hickory_resolver::TokioAsyncResolver;
+use std::net::IpAddr;
+
+pub fn build_resolver(dns_override: Option<IpAddr>) -> Result<TokioAsyncResolver> {
+    let (config, opts) = match dns_override {
+        Some(addr) => (
+            ResolverConfig::from_parts(
+                None,
+                vec![],
+                NameServerConfigGroup::from_ips_clear(&[addr], 53, true),
+            ),
+            ResolverOpts::default(),
+        ),
+        None => hickory_resolver::system_conf::read_system_conf()
+            .context("failed to read system DNS configuration")?,
+    };
+
+    Ok(TokioAsyncResolver::tokio(config, opts))
+}
+
+/// Resolve `host` to an IP address string. If it already parses as an IP,
+/// it's returned unchanged without touching the resolver.
+pub async fn resolve(resolver: &TokioAsyncResolver, host: &str) -> Result<String> {
+    if host.parse::<IpAddr>().is_ok() {
+        return Ok(host.to_string());
+    }
+
+    let response = resolver
+        .lookup_ip(host)
+        .await
+        .with_context(|| format!("failed to resolve {}", host))?;
+
+    response
+        .iter()
+        .next()
+        .map(|ip| ip.to_string())
+        .with_context(|| format!("no addresses found for {}", host))
+}
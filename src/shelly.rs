@@ -0,0 +1,238 @@
+//! Talking to a Shelly device's HTTP API and normalizing its Gen1 and Gen2+
+//! response shapes into one generation-agnostic set of readings, so the
+//! InfluxDB push path and the Prometheus exporter can share the same parsing.
+
+use crate::discover::{ShellyDevice, ShellyGeneration};
+use anyhow::{Context, Result};
+use std::time::Duration;
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// One channel's readings. Gen1 meters/relays and Gen2+ `switch:N` objects
+/// both collapse into this shape.
+#[derive(Debug, Default, Clone)]
+pub struct ChannelMetrics {
+    pub channel: usize,
+    pub power_watts: Option<f64>,
+    pub voltage: Option<f64>,
+    pub current: Option<f64>,
+    pub energy_total_wh: Option<f64>,
+    pub relay_on: Option<bool>,
+    pub temperature_c: Option<f64>,
+}
+
+/// All readings scraped from a single device in one pass.
+#[derive(Debug, Default, Clone)]
+pub struct DeviceMetrics {
+    pub channels: Vec<ChannelMetrics>,
+    /// Whole-device temperature, only reported by some Gen1 devices.
+    pub temperature_c: Option<f64>,
+    pub wifi_rssi_dbm: Option<i64>,
+}
+
+/// Detect the device's generation (unless already known) and scrape it.
+pub async fn scrape_device(client: &reqwest::Client, device: &ShellyDevice) -> Result<DeviceMetrics> {
+    let generation = match device.generation_hint {
+        Some(generation) => generation,
+        None => detect_generation(client, &device.ip, device.port).await?,
+    };
+
+    match generation {
+        ShellyGeneration::Gen1 => scrape_gen1(client, &device.ip, device.port).await,
+        ShellyGeneration::Gen2Plus => scrape_gen2(client, &device.ip, device.port).await,
+    }
+}
+
+/// Build the base URL for a device, honoring its advertised HTTP port (read
+/// from an mDNS SRV record) instead of always assuming port 80.
+fn base_url(ip: &str, port: Option<u16>) -> String {
+    match port {
+        Some(port) => format!("http://{}:{}", ip, port),
+        None => format!("http://{}", ip),
+    }
+}
+
+/// Probe `GET /shelly`, which every Shelly device answers regardless of generation,
+/// and use the presence of a `gen` field to tell Gen1 and Gen2+ devices apart.
+pub async fn detect_generation(client: &reqwest::Client, ip: &str, port: Option<u16>) -> Result<ShellyGeneration> {
+    let url = format!("{}/shelly", base_url(ip, port));
+    let body: serde_json::Value = client
+        .get(&url)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("failed to query {}", url))?
+        .error_for_status()
+        .with_context(|| format!("{} returned an error status", url))?
+        .json()
+        .await
+        .with_context(|| format!("failed to parse JSON from {}", url))?;
+
+    if body.get("gen").is_some() {
+        Ok(ShellyGeneration::Gen2Plus)
+    } else {
+        Ok(ShellyGeneration::Gen1)
+    }
+}
+
+/// Scrape a Gen1 device via `GET /status`.
+async fn scrape_gen1(client: &reqwest::Client, ip: &str, port: Option<u16>) -> Result<DeviceMetrics> {
+    let url = format!("{}/status", base_url(ip, port));
+    let status: serde_json::Value = client
+        .get(&url)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("failed to query {}", url))?
+        .error_for_status()
+        .with_context(|| format!("{} returned an error status", url))?
+        .json()
+        .await
+        .with_context(|| format!("failed to parse JSON from {}", url))?;
+
+    Ok(parse_gen1_status(&status))
+}
+
+/// Pull `DeviceMetrics` out of a Gen1 `/status` response body.
+fn parse_gen1_status(status: &serde_json::Value) -> DeviceMetrics {
+    let mut channels = Vec::new();
+
+    if let Some(meters) = status.get("meters").and_then(|m| m.as_array()) {
+        for (index, meter) in meters.iter().enumerate() {
+            let is_valid = meter.get("is_valid").and_then(|v| v.as_bool()).unwrap_or(true);
+            if !is_valid {
+                continue;
+            }
+            channel_mut(&mut channels, index).power_watts = meter.get("power").and_then(|v| v.as_f64());
+            channel_mut(&mut channels, index).energy_total_wh = meter.get("total").and_then(|v| v.as_f64());
+        }
+    }
+
+    if let Some(relays) = status.get("relays").and_then(|r| r.as_array()) {
+        for (index, relay) in relays.iter().enumerate() {
+            if let Some(is_on) = relay.get("ison").and_then(|v| v.as_bool()) {
+                channel_mut(&mut channels, index).relay_on = Some(is_on);
+            }
+        }
+    }
+
+    channels.retain(|channel| {
+        channel.power_watts.is_some()
+            || channel.energy_total_wh.is_some()
+            || channel.relay_on.is_some()
+    });
+
+    let temperature_c = status.get("tmp").and_then(|t| t.get("tC")).and_then(|v| v.as_f64());
+    let wifi_rssi_dbm = status
+        .get("wifi_sta")
+        .and_then(|w| w.get("rssi"))
+        .and_then(|v| v.as_i64());
+
+    DeviceMetrics { channels, temperature_c, wifi_rssi_dbm }
+}
+
+/// Scrape a Gen2+ device via `/rpc/Shelly.GetStatus`, reading its `switch:N`
+/// objects until one is missing.
+async fn scrape_gen2(client: &reqwest::Client, ip: &str, port: Option<u16>) -> Result<DeviceMetrics> {
+    let url = format!("{}/rpc/Shelly.GetStatus", base_url(ip, port));
+    let status: serde_json::Value = client
+        .get(&url)
+        .timeout(REQUEST_TIMEOUT)
+        .send()
+        .await
+        .with_context(|| format!("failed to query {}", url))?
+        .error_for_status()
+        .with_context(|| format!("{} returned an error status", url))?
+        .json()
+        .await
+        .with_context(|| format!("failed to parse JSON from {}", url))?;
+
+    Ok(parse_gen2_status(&status))
+}
+
+/// Pull `DeviceMetrics` out of a Gen2+ `/rpc/Shelly.GetStatus` response body.
+fn parse_gen2_status(status: &serde_json::Value) -> DeviceMetrics {
+    let mut channels = Vec::new();
+
+    for index in 0.. {
+        let Some(switch) = status.get(format!("switch:{}", index)) else {
+            break;
+        };
+
+        let channel = channel_mut(&mut channels, index);
+        channel.power_watts = switch.get("apower").and_then(|v| v.as_f64());
+        channel.voltage = switch.get("voltage").and_then(|v| v.as_f64());
+        channel.current = switch.get("current").and_then(|v| v.as_f64());
+        channel.energy_total_wh = switch
+            .get("aenergy")
+            .and_then(|a| a.get("total"))
+            .and_then(|v| v.as_f64());
+        channel.temperature_c = switch
+            .get("temperature")
+            .and_then(|t| t.get("tC"))
+            .and_then(|v| v.as_f64());
+    }
+
+    DeviceMetrics { channels, temperature_c: None, wifi_rssi_dbm: None }
+}
+
+/// Get or create the `ChannelMetrics` for `index`, growing the vec as needed.
+fn channel_mut(channels: &mut Vec<ChannelMetrics>, index: usize) -> &mut ChannelMetrics {
+    while channels.len() <= index {
+        channels.push(ChannelMetrics { channel: channels.len(), ..Default::default() });
+    }
+    &mut channels[index]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn parses_gen1_meters_and_relays() {
+        let status = json!({
+            "meters": [{"power": 12.5, "total": 340.0, "is_valid": true}],
+            "relays": [{"ison": true}],
+            "tmp": {"tC": 21.5},
+            "wifi_sta": {"rssi": -55},
+        });
+
+        let metrics = parse_gen1_status(&status);
+
+        assert_eq!(metrics.channels.len(), 1);
+        assert_eq!(metrics.channels[0].power_watts, Some(12.5));
+        assert_eq!(metrics.channels[0].energy_total_wh, Some(340.0));
+        assert_eq!(metrics.channels[0].relay_on, Some(true));
+        assert_eq!(metrics.temperature_c, Some(21.5));
+        assert_eq!(metrics.wifi_rssi_dbm, Some(-55));
+    }
+
+    #[test]
+    fn skips_invalid_gen1_meters() {
+        let status = json!({
+            "meters": [{"power": 12.5, "is_valid": false}],
+        });
+
+        let metrics = parse_gen1_status(&status);
+
+        assert!(metrics.channels.is_empty());
+    }
+
+    #[test]
+    fn parses_gen2_switches_until_a_gap() {
+        let status = json!({
+            "switch:0": {"apower": 5.0, "voltage": 230.0, "aenergy": {"total": 10.0}},
+            "switch:1": {"apower": 7.5},
+            "switch:3": {"apower": 1.0},
+        });
+
+        let metrics = parse_gen2_status(&status);
+
+        assert_eq!(metrics.channels.len(), 2);
+        assert_eq!(metrics.channels[0].power_watts, Some(5.0));
+        assert_eq!(metrics.channels[0].voltage, Some(230.0));
+        assert_eq!(metrics.channels[0].energy_total_wh, Some(10.0));
+        assert_eq!(metrics.channels[1].power_watts, Some(7.5));
+    }
+}